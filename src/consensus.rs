@@ -8,12 +8,32 @@ use crate::sn_membership::Generation;
 use crate::vote::{Ballot, Proposition, SignedVote, Vote};
 use crate::{Error, NodeId, Result};
 
+/// Maximum number of votes buffered for a single not-yet-reached generation.
+const MAX_PENDING_VOTES_PER_GEN: usize = 100;
+/// Maximum number of votes buffered across all not-yet-reached generations combined.
+const MAX_PENDING_VOTES_TOTAL: usize = 1000;
+
 #[derive(Debug)]
 pub struct Consensus<T: Proposition> {
     pub elders: PublicKeySet,
     pub n_elders: usize,
     pub secret_key: (NodeId, SecretKeyShare),
-    pub votes: BTreeMap<NodeId, SignedVote<T>>,
+    pub votes: VoteCollector<T>,
+    /// Votes for generations we haven't reached yet, buffered until `advance_to_generation`.
+    pub pending: BTreeMap<Generation, Vec<SignedVote<T>>>,
+    /// Decision signature shares, keyed by `(gen, proposals)` then by signer.
+    pub decision_shares: BTreeMap<(Generation, BTreeSet<T>), BTreeMap<NodeId, SignatureShare>>,
+    /// Evidence of Byzantine misbehaviour observed while logging votes.
+    pub fault_log: FaultLog<T>,
+    /// When we last (re)broadcast our vote for a generation, checked by `handle_timeout`.
+    pub last_broadcast: BTreeMap<Generation, std::time::Instant>,
+    /// Elders' `PublicKeySet` recorded at the generation it became active.
+    pub elders_history: BTreeMap<Generation, PublicKeySet>,
+    /// Elder count recorded at the generation it became active, alongside `elders_history`.
+    pub n_elders_history: BTreeMap<Generation, usize>,
+    /// Our secret key share recorded at the generation it became active, so votes for a
+    /// generation sign (and verify) with the share that was active at the time.
+    pub secret_key_history: BTreeMap<Generation, SecretKeyShare>,
 }
 
 pub enum VoteResponse<T: Proposition> {
@@ -22,6 +42,101 @@ pub enum VoteResponse<T: Proposition> {
     Decided(SignedVote<T>),
 }
 
+/// Tallies each voter's latest vote, per generation, across multiple concurrent generations.
+#[derive(Debug)]
+pub struct VoteCollector<T: Proposition> {
+    votes: BTreeMap<Generation, BTreeMap<NodeId, SignedVote<T>>>,
+}
+
+impl<T: Proposition> Default for VoteCollector<T> {
+    fn default() -> Self {
+        VoteCollector {
+            votes: Default::default(),
+        }
+    }
+}
+
+impl<T: Proposition> VoteCollector<T> {
+    /// Logs `vote`, keeping at most one vote per voter per generation. Returns the existing
+    /// vote if it's incompatible with `vote`, for the caller to record as equivocation.
+    fn log_signed_vote(&mut self, vote: &SignedVote<T>) -> Option<SignedVote<T>> {
+        let gen_votes = self.votes.entry(vote.vote.gen).or_default();
+        match gen_votes.get(&vote.voter).cloned() {
+            None => {
+                gen_votes.insert(vote.voter, vote.clone());
+                None
+            }
+            Some(existing_vote) if vote.supersedes(&existing_vote) => {
+                gen_votes.insert(vote.voter, vote.clone());
+                None
+            }
+            Some(existing_vote) if existing_vote.supersedes(vote) => None,
+            Some(existing_vote) => Some(existing_vote),
+        }
+    }
+
+    /// Returns the vote logged for voter `id` during `gen`, if it has voted.
+    fn our_vote(&self, gen: Generation, id: NodeId) -> Option<SignedVote<T>> {
+        self.votes.get(&gen).and_then(|votes| votes.get(&id)).cloned()
+    }
+
+    /// Returns the current tally of votes logged for `gen`.
+    fn for_gen(&self, gen: Generation) -> BTreeSet<SignedVote<T>> {
+        self.votes
+            .get(&gen)
+            .map(|votes| votes.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Erases a generation's tallies once it has been finalized.
+    fn reset_gen(&mut self, gen: Generation) {
+        self.votes.remove(&gen);
+    }
+}
+
+/// A compact, standalone proof that `gen` decided on `proposals`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DecisionCertificate<T: Proposition> {
+    pub gen: Generation,
+    pub proposals: BTreeSet<T>,
+    pub sig: blsttc::Signature,
+}
+
+/// A verifiable proof that `voter` signed two incompatible votes for the same generation.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Equivocation<T: Proposition> {
+    pub voter: NodeId,
+    pub gen: Generation,
+    pub vote_a: SignedVote<T>,
+    pub vote_b: SignedVote<T>,
+}
+
+/// Accumulates verifiable evidence of Byzantine misbehaviour observed while processing votes.
+#[derive(Debug)]
+pub struct FaultLog<T: Proposition> {
+    equivocations: Vec<Equivocation<T>>,
+}
+
+impl<T: Proposition> Default for FaultLog<T> {
+    fn default() -> Self {
+        FaultLog {
+            equivocations: Default::default(),
+        }
+    }
+}
+
+impl<T: Proposition> FaultLog<T> {
+    fn record_equivocation(&mut self, voter: NodeId, vote_a: SignedVote<T>, vote_b: SignedVote<T>) {
+        let gen = vote_a.vote.gen;
+        self.equivocations.push(Equivocation {
+            voter,
+            gen,
+            vote_a,
+            vote_b,
+        });
+    }
+}
+
 impl<T: Proposition> Consensus<T> {
     pub fn from(
         secret_key: (NodeId, SecretKeyShare),
@@ -29,20 +144,73 @@ impl<T: Proposition> Consensus<T> {
         n_elders: usize,
     ) -> Self {
         Consensus::<T> {
-            elders,
+            elders: elders.clone(),
             n_elders,
             secret_key,
             votes: Default::default(),
+            pending: Default::default(),
+            decision_shares: Default::default(),
+            fault_log: Default::default(),
+            last_broadcast: Default::default(),
+            elders_history: BTreeMap::from([(Generation::default(), elders)]),
+            n_elders_history: BTreeMap::from([(Generation::default(), n_elders)]),
+            secret_key_history: BTreeMap::from([(Generation::default(), secret_key.1.clone())]),
         }
     }
 
+    /// Returns the `PublicKeySet` that was active for `gen`.
+    fn public_key_set_for_gen(&self, gen: Generation) -> &PublicKeySet {
+        self.elders_history
+            .range(..=gen)
+            .next_back()
+            .map(|(_, key_set)| key_set)
+            .unwrap_or(&self.elders)
+    }
+
+    /// Returns the elder count that was active for `gen`.
+    fn n_elders_for_gen(&self, gen: Generation) -> usize {
+        self.n_elders_history
+            .range(..=gen)
+            .next_back()
+            .map(|(_, n_elders)| *n_elders)
+            .unwrap_or(self.n_elders)
+    }
+
+    /// Returns our own secret key share that was active for `gen`.
+    fn secret_key_share_for_gen(&self, gen: Generation) -> &SecretKeyShare {
+        self.secret_key_history
+            .range(..=gen)
+            .next_back()
+            .map(|(_, share)| share)
+            .unwrap_or(&self.secret_key.1)
+    }
+
+    /// Adopts freshly generated key material for `gen`, once a DKG round among the elders
+    /// has completed. Votes before `gen` keep verifying (and signing) against whichever
+    /// elder set and secret share was active for them at the time.
+    pub fn adopt_key_material(
+        &mut self,
+        gen: Generation,
+        new_elders: PublicKeySet,
+        new_n_elders: usize,
+        new_secret_share: SecretKeyShare,
+    ) {
+        self.elders_history.insert(gen, new_elders.clone());
+        self.n_elders_history.insert(gen, new_n_elders);
+        self.secret_key_history.insert(gen, new_secret_share.clone());
+        self.elders = new_elders;
+        self.n_elders = new_n_elders;
+        self.secret_key = (self.secret_key.0, new_secret_share);
+    }
+
     pub fn verify_sig_share<M: Serialize>(
         &self,
+        gen: Generation,
         msg: &M,
         elder: NodeId,
         sig: &SignatureShare,
     ) -> Result<()> {
-        let public_key = self.elders.public_key_share(elder as u64);
+        let public_key = self.public_key_set_for_gen(gen).public_key_share(elder as u64);
         let msg_bytes = bincode::serialize(msg)?;
         if public_key.verify(sig, msg_bytes) {
             Ok(())
@@ -51,8 +219,8 @@ impl<T: Proposition> Consensus<T> {
         }
     }
 
-    pub fn sign<M: Serialize>(&self, msg: &M) -> Result<SignatureShare> {
-        Ok(self.secret_key.1.sign(&bincode::serialize(msg)?))
+    pub fn sign<M: Serialize>(&self, gen: Generation, msg: &M) -> Result<SignatureShare> {
+        Ok(self.secret_key_share_for_gen(gen).sign(&bincode::serialize(msg)?))
     }
 
     pub fn id(&self) -> NodeId {
@@ -60,12 +228,12 @@ impl<T: Proposition> Consensus<T> {
     }
 
     pub fn build_super_majority_vote(&self, gen: Generation) -> Result<SignedVote<T>> {
-        let votes = self.votes.values().cloned().collect();
+        let votes = self.votes.for_gen(gen);
         let proposals: BTreeMap<T, (NodeId, SignatureShare)> = self
             .proposals(&votes)
             .into_iter()
             .map(|p| {
-                let sig = self.sign(&p)?;
+                let sig = self.sign(gen, &p)?;
                 Ok((p, (self.secret_key.0, sig)))
             })
             .collect::<Result<_>>()?;
@@ -74,6 +242,56 @@ impl<T: Proposition> Consensus<T> {
         self.sign_vote(vote)
     }
 
+    /// Signs the canonical decision message for `gen` having reached agreement on `proposals`.
+    pub fn sign_decision(&self, gen: Generation, proposals: &BTreeSet<T>) -> Result<SignatureShare> {
+        self.sign(gen, &(gen, proposals))
+    }
+
+    /// Records a decision signature share, returning the combined `DecisionCertificate`
+    /// once enough shares for `(gen, proposals)` have crossed the threshold.
+    pub fn handle_decision_share(
+        &mut self,
+        gen: Generation,
+        proposals: BTreeSet<T>,
+        voter: NodeId,
+        sig: SignatureShare,
+    ) -> Result<Option<DecisionCertificate<T>>> {
+        self.verify_sig_share(gen, &(gen, &proposals), voter, &sig)?;
+
+        let key = (gen, proposals.clone());
+        let shares = self.decision_shares.entry(key.clone()).or_default();
+        shares.insert(voter, sig);
+
+        if shares.len() > self.public_key_set_for_gen(gen).threshold() {
+            let cert_sig = self
+                .public_key_set_for_gen(gen)
+                .combine_signatures(shares.iter().map(|(id, sig)| (*id as u64, sig)))
+                .map_err(|_| Error::InvalidElderSignature)?;
+            self.decision_shares.remove(&key);
+            Ok(Some(DecisionCertificate {
+                gen,
+                proposals,
+                sig: cert_sig,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Verifies a `DecisionCertificate` against the elders' group public key for its `gen`.
+    pub fn verify_decision(&self, cert: &DecisionCertificate<T>) -> Result<()> {
+        let msg_bytes = bincode::serialize(&(cert.gen, &cert.proposals))?;
+        if self
+            .public_key_set_for_gen(cert.gen)
+            .public_key()
+            .verify(&cert.sig, msg_bytes)
+        {
+            Ok(())
+        } else {
+            Err(Error::InvalidElderSignature)
+        }
+    }
+
     // handover: gen = gen
     // membership: gen = pending_gen
     /// Handles a signed vote
@@ -83,17 +301,26 @@ impl<T: Proposition> Consensus<T> {
         signed_vote: SignedVote<T>,
         gen: Generation,
     ) -> Result<VoteResponse<T>> {
+        if signed_vote.vote.gen > gen {
+            return self.stash_vote_for_future_generation(signed_vote, gen);
+        }
+        if signed_vote.vote.gen < gen {
+            // Stale vote for a generation we've already moved past: nothing to replay it
+            // into, so there's no point paying to validate and buffer it.
+            return Ok(VoteResponse::WaitingForMoreVotes);
+        }
+
         self.log_signed_vote(&signed_vote);
 
-        if self.is_split_vote(&self.votes.values().cloned().collect()) {
+        if self.is_split_vote(gen, &self.votes.for_gen(gen)) {
             info!("[MBR] Detected split vote");
             let merge_vote = Vote {
                 gen,
-                ballot: Ballot::Merge(self.votes.values().cloned().collect()).simplify(),
+                ballot: Ballot::Merge(self.votes.for_gen(gen)).simplify(),
             };
             let signed_merge_vote = self.sign_vote(merge_vote)?;
 
-            if let Some(our_vote) = self.votes.get(&self.id()) {
+            if let Some(our_vote) = self.votes.our_vote(gen, self.id()) {
                 let proposals_we_voted_for = our_vote.proposals();
                 let proposals_we_would_vote_for = signed_merge_vote.proposals();
 
@@ -107,16 +334,16 @@ impl<T: Proposition> Consensus<T> {
             return Ok(VoteResponse::Broadcast(self.cast_vote(signed_merge_vote)));
         }
 
-        if self.is_super_majority_over_super_majorities(&self.votes.values().cloned().collect()) {
+        if self.is_super_majority_over_super_majorities(gen, &self.votes.for_gen(gen)) {
             info!("[MBR] Detected super majority over super majorities");
             // return obtained super majority over super majority (aka consensus)
             return Ok(VoteResponse::Decided(self.build_super_majority_vote(gen)?));
         }
 
-        if self.is_super_majority(&self.votes.values().cloned().collect()) {
+        if self.is_super_majority(gen, &self.votes.for_gen(gen)) {
             info!("[MBR] Detected super majority");
 
-            if let Some(our_vote) = self.votes.get(&self.id()) {
+            if let Some(our_vote) = self.votes.our_vote(gen, self.id()) {
                 // We voted during this generation.
 
                 if our_vote.vote.is_super_majority_ballot() {
@@ -132,7 +359,7 @@ impl<T: Proposition> Consensus<T> {
 
         // We have determined that we don't yet have enough votes to take action.
         // If we have not yet voted, this is where we would contribute our vote
-        if !self.votes.contains_key(&self.id()) {
+        if self.votes.our_vote(gen, self.id()).is_none() {
             let signed_vote = self.sign_vote(Vote {
                 gen,
                 ballot: signed_vote.vote.ballot,
@@ -143,28 +370,184 @@ impl<T: Proposition> Consensus<T> {
         Ok(VoteResponse::WaitingForMoreVotes)
     }
 
+    /// Called after `interval` has elapsed with no progress on `gen`, to recover from a lost
+    /// broadcast. Re-broadcasts our latest vote, escalating to merge/super-majority as the
+    /// votes we hold now warrant. Does nothing if we haven't voted yet, or not enough time
+    /// has passed since our last (re)broadcast for this generation.
+    pub fn handle_timeout(
+        &mut self,
+        gen: Generation,
+        interval: std::time::Duration,
+    ) -> Result<VoteResponse<T>> {
+        let Some(our_vote) = self.votes.our_vote(gen, self.id()) else {
+            return Ok(VoteResponse::WaitingForMoreVotes);
+        };
+
+        let should_rebroadcast = match self.last_broadcast.get(&gen) {
+            Some(last) => last.elapsed() >= interval,
+            None => true,
+        };
+        if !should_rebroadcast {
+            return Ok(VoteResponse::WaitingForMoreVotes);
+        }
+
+        let votes = self.votes.for_gen(gen);
+        if self.is_super_majority_over_super_majorities(gen, &votes) {
+            info!("[MBR] Timeout: we already have a decision, re-broadcasting it");
+            self.last_broadcast.insert(gen, std::time::Instant::now());
+            return Ok(VoteResponse::Decided(self.build_super_majority_vote(gen)?));
+        }
+
+        if self.is_split_vote(gen, &votes) {
+            info!("[MBR] Timeout: stalled on a split vote, escalating to merge");
+            let merge_vote = Vote {
+                gen,
+                ballot: Ballot::Merge(votes).simplify(),
+            };
+            let signed_merge_vote = self.sign_vote(merge_vote)?;
+            return Ok(VoteResponse::Broadcast(self.cast_vote(signed_merge_vote)));
+        }
+
+        if self.is_super_majority(gen, &votes) && !our_vote.vote.is_super_majority_ballot() {
+            info!("[MBR] Timeout: escalating to a super majority vote");
+            let signed_vote = self.build_super_majority_vote(gen)?;
+            return Ok(VoteResponse::Broadcast(self.cast_vote(signed_vote)));
+        }
+
+        info!("[MBR] Timeout: re-broadcasting our latest vote for gen {gen}");
+        let signed_vote = self.sign_vote(our_vote.vote)?;
+        Ok(VoteResponse::Broadcast(self.cast_vote(signed_vote)))
+    }
+
     pub fn sign_vote(&self, vote: Vote<T>) -> Result<SignedVote<T>> {
         Ok(SignedVote {
             voter: self.secret_key.0,
-            sig: self.sign(&vote)?,
+            sig: self.sign(vote.gen, &vote)?,
             vote,
         })
     }
 
     pub fn cast_vote(&mut self, signed_vote: SignedVote<T>) -> SignedVote<T> {
+        self.last_broadcast
+            .insert(signed_vote.vote.gen, std::time::Instant::now());
         self.log_signed_vote(&signed_vote);
         signed_vote
     }
 
     pub fn log_signed_vote(&mut self, signed_vote: &SignedVote<T>) {
         for vote in signed_vote.unpack_votes() {
-            let existing_vote = self.votes.entry(vote.voter).or_insert_with(|| vote.clone());
-            if vote.supersedes(existing_vote) {
-                *existing_vote = vote.clone()
+            if let Some(existing_vote) = self.votes.log_signed_vote(vote) {
+                // Neither vote supersedes the other: the voter signed two incompatible
+                // votes for the same generation.
+                self.fault_log
+                    .record_equivocation(vote.voter, existing_vote, vote.clone());
+            }
+        }
+    }
+
+    /// Returns the current tally of votes for `gen`, keyed by proposal set.
+    pub fn count_votes_for_gen(&self, gen: Generation) -> BTreeMap<BTreeSet<T>, usize> {
+        self.count_votes(&self.votes.for_gen(gen))
+    }
+
+    /// Erases the vote tallies for `gen`, e.g. once it has been finalized and superseded
+    /// by a new era, freeing the `Consensus` to forget about it.
+    pub fn finalize_generation(&mut self, gen: Generation) {
+        self.votes.reset_gen(gen);
+    }
+
+    /// Re-checks both signature shares in `proof` against the voter's `public_key_share` to
+    /// confirm the equivocation is genuine and attributable.
+    pub fn verify_fault(&self, proof: &Equivocation<T>) -> Result<()> {
+        if proof.vote_a.voter != proof.voter
+            || proof.vote_b.voter != proof.voter
+            || proof.vote_a.vote.gen != proof.gen
+            || proof.vote_b.vote.gen != proof.gen
+        {
+            return Err(Error::InvalidElderSignature);
+        }
+
+        // A genuine equivocation requires neither vote to supersede the other; two
+        // validly-signed but compatible votes (e.g. the same vote twice) aren't a fault.
+        if proof.vote_a.supersedes(&proof.vote_b) || proof.vote_b.supersedes(&proof.vote_a) {
+            return Err(Error::InvalidElderSignature);
+        }
+
+        self.verify_sig_share(proof.gen, &proof.vote_a.vote, proof.voter, &proof.vote_a.sig)?;
+        self.verify_sig_share(proof.gen, &proof.vote_b.vote, proof.voter, &proof.vote_b.sig)?;
+        Ok(())
+    }
+
+    /// Drains and returns the accumulated fault evidence.
+    pub fn take_faults(&mut self) -> Vec<Equivocation<T>> {
+        std::mem::take(&mut self.fault_log.equivocations)
+    }
+
+    /// Validates and stashes a vote for a generation we haven't reached yet, to be replayed
+    /// once `advance_to_generation` gets there.
+    fn stash_vote_for_future_generation(
+        &mut self,
+        signed_vote: SignedVote<T>,
+        gen: Generation,
+    ) -> Result<VoteResponse<T>> {
+        self.verify_sig_share(
+            signed_vote.vote.gen,
+            &signed_vote.vote,
+            signed_vote.voter,
+            &signed_vote.sig,
+        )?;
+        self.validate_vote(&signed_vote.vote)?;
+
+        let target_gen = signed_vote.vote.gen;
+        info!("[MBR] Buffering vote for not-yet-reached generation {target_gen} (currently at {gen})");
+        self.pending.entry(target_gen).or_default().push(signed_vote);
+        self.enforce_pending_caps();
+
+        Ok(VoteResponse::WaitingForMoreVotes)
+    }
+
+    /// Trims `pending` down to the per-generation and total caps, evicting the highest
+    /// generation first so a flood of far-future votes can't crowd out near-term ones.
+    fn enforce_pending_caps(&mut self) {
+        for votes in self.pending.values_mut() {
+            while votes.len() > MAX_PENDING_VOTES_PER_GEN {
+                votes.remove(0);
+            }
+        }
+
+        let mut total: usize = self.pending.values().map(Vec::len).sum();
+        while total > MAX_PENDING_VOTES_TOTAL {
+            let Some(&highest_gen) = self.pending.keys().next_back() else {
+                break;
+            };
+            let Some(votes) = self.pending.get_mut(&highest_gen) else {
+                break;
+            };
+            if !votes.is_empty() {
+                votes.remove(0);
+                total -= 1;
+            }
+            if votes.is_empty() {
+                self.pending.remove(&highest_gen);
             }
         }
     }
 
+    /// Advances processing to `gen`, replaying any votes previously buffered for it.
+    pub fn advance_to_generation(&mut self, gen: Generation) -> Result<VoteResponse<T>> {
+        let stashed_votes = self.pending.remove(&gen).unwrap_or_default();
+
+        let mut response = VoteResponse::WaitingForMoreVotes;
+        for vote in stashed_votes {
+            response = self.handle_signed_vote(vote, gen)?;
+            if matches!(response, VoteResponse::Decided(_)) {
+                break;
+            }
+        }
+
+        Ok(response)
+    }
+
     pub fn count_votes(&self, votes: &BTreeSet<SignedVote<T>>) -> BTreeMap<BTreeSet<T>, usize> {
         let mut count: BTreeMap<BTreeSet<T>, usize> = Default::default();
 
@@ -181,19 +564,20 @@ impl<T: Proposition> Consensus<T> {
         BTreeSet::from_iter(votes.iter().flat_map(|v| v.proposals()))
     }
 
-    fn is_split_vote(&self, votes: &BTreeSet<SignedVote<T>>) -> bool {
+    fn is_split_vote(&self, gen: Generation, votes: &BTreeSet<SignedVote<T>>) -> bool {
         let counts = self.count_votes(votes);
         let most_votes = counts.values().max().cloned().unwrap_or_default();
         let voters = BTreeSet::from_iter(votes.iter().map(|v| v.voter));
-        let remaining_voters = self.n_elders - voters.len();
+        let remaining_voters = self.n_elders_for_gen(gen) - voters.len();
 
         // give the remaining votes to the proposals with the most votes.
         let predicted_votes = most_votes + remaining_voters;
 
-        voters.len() > self.elders.threshold() && predicted_votes <= self.elders.threshold()
+        let threshold = self.public_key_set_for_gen(gen).threshold();
+        voters.len() > threshold && predicted_votes <= threshold
     }
 
-    pub fn is_super_majority(&self, votes: &BTreeSet<SignedVote<T>>) -> bool {
+    pub fn is_super_majority(&self, gen: Generation, votes: &BTreeSet<SignedVote<T>>) -> bool {
         // TODO: super majority should always just be the largest 7 members
         let most_votes = self
             .count_votes(votes)
@@ -202,10 +586,14 @@ impl<T: Proposition> Consensus<T> {
             .cloned()
             .unwrap_or_default();
 
-        most_votes > self.elders.threshold()
+        most_votes > self.public_key_set_for_gen(gen).threshold()
     }
 
-    fn is_super_majority_over_super_majorities(&self, votes: &BTreeSet<SignedVote<T>>) -> bool {
+    fn is_super_majority_over_super_majorities(
+        &self,
+        gen: Generation,
+        votes: &BTreeSet<SignedVote<T>>,
+    ) -> bool {
         let count_of_agreeing_super_majorities = self
             .count_votes(&BTreeSet::from_iter(
                 votes
@@ -218,13 +606,18 @@ impl<T: Proposition> Consensus<T> {
             .max()
             .unwrap_or(0);
 
-        count_of_agreeing_super_majorities > self.elders.threshold()
+        count_of_agreeing_super_majorities > self.public_key_set_for_gen(gen).threshold()
     }
 
     /// Validates a vote recursively all the way down to the proposition (T)
     /// Assumes those propositions are correct, they MUST be checked beforehand by the caller
     pub fn validate_signed_vote(&self, signed_vote: &SignedVote<T>) -> Result<()> {
-        self.verify_sig_share(&signed_vote.vote, signed_vote.voter, &signed_vote.sig)?;
+        self.verify_sig_share(
+            signed_vote.vote.gen,
+            &signed_vote.vote,
+            signed_vote.voter,
+            &signed_vote.sig,
+        )?;
         self.validate_vote(&signed_vote.vote)?;
         self.validate_vote_supersedes_existing_vote(signed_vote)?;
         Ok(())
@@ -247,6 +640,7 @@ impl<T: Proposition> Consensus<T> {
             }
             Ballot::SuperMajority { votes, proposals } => {
                 if !self.is_super_majority(
+                    vote.gen,
                     &votes
                         .iter()
                         .flat_map(SignedVote::unpack_votes)
@@ -258,7 +652,7 @@ impl<T: Proposition> Consensus<T> {
                     Err(Error::SuperMajorityProposalsDoesNotMatchVoteProposals)
                 } else if proposals
                     .iter()
-                    .try_for_each(|(p, (id, sig))| self.verify_sig_share(&p, *id, sig))
+                    .try_for_each(|(p, (id, sig))| self.verify_sig_share(vote.gen, &p, *id, sig))
                     .is_err()
                 {
                     Err(Error::InvalidElderSignature)
@@ -279,13 +673,405 @@ impl<T: Proposition> Consensus<T> {
     }
 
     fn validate_vote_supersedes_existing_vote(&self, signed_vote: &SignedVote<T>) -> Result<()> {
-        if self.votes.contains_key(&signed_vote.voter)
-            && !signed_vote.supersedes(&self.votes[&signed_vote.voter])
-            && !self.votes[&signed_vote.voter].supersedes(signed_vote)
-        {
-            Err(Error::ExistingVoteIncompatibleWithNewVote)
-        } else {
-            Ok(())
+        match self.votes.our_vote(signed_vote.vote.gen, signed_vote.voter) {
+            Some(existing_vote)
+                if !signed_vote.supersedes(&existing_vote)
+                    && !existing_vote.supersedes(signed_vote) =>
+            {
+                Err(Error::ExistingVoteIncompatibleWithNewVote)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A synchronous, dealerless distributed key generation round for resharing the elders'
+/// threshold key to a new elder set.
+pub mod dkg {
+    use std::collections::BTreeMap;
+
+    use blsttc::poly::{BivarCommitment, BivarPoly, Poly};
+    use blsttc::{PublicKeySet, SecretKeyShare};
+    use rand::rngs::OsRng;
+
+    use crate::{Error, NodeId, Result};
+
+    /// A participant's commitment to its secret bivariate polynomial, plus the row of that
+    /// polynomial owed to every other participant.
+    #[derive(Debug, Clone)]
+    pub struct Part {
+        pub dealer: NodeId,
+        pub commitment: BivarCommitment,
+        pub rows: BTreeMap<NodeId, Poly>,
+    }
+
+    /// A participant's acknowledgement that it received and checked a `Part`.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Ack {
+        pub dealer: NodeId,
+        pub acker: NodeId,
+        pub valid: bool,
+    }
+
+    /// Drives our side of a synchronous dealerless DKG round among `participants`.
+    pub struct DkgSession {
+        our_id: NodeId,
+        threshold: usize,
+        participants: Vec<NodeId>,
+        our_poly: BivarPoly,
+        parts: BTreeMap<NodeId, Part>,
+    }
+
+    impl DkgSession {
+        pub fn new(our_id: NodeId, threshold: usize, participants: Vec<NodeId>) -> Self {
+            DkgSession {
+                our_id,
+                threshold,
+                participants,
+                our_poly: BivarPoly::random(threshold, &mut OsRng),
+                parts: Default::default(),
+            }
+        }
+
+        /// `x = 0` is reserved for the master-secret row, so participants are indexed from 1.
+        fn eval_point(id: NodeId) -> u64 {
+            id as u64 + 1
+        }
+
+        /// Builds the `Part` we broadcast to every other participant.
+        pub fn our_part(&self) -> Part {
+            let rows = self
+                .participants
+                .iter()
+                .map(|&id| (id, self.our_poly.row(Self::eval_point(id))))
+                .collect();
+            Part {
+                dealer: self.our_id,
+                commitment: self.our_poly.commitment(),
+                rows,
+            }
+        }
+
+        /// Validates a `Part`, records it, and returns the `Ack` we should broadcast for it.
+        pub fn handle_part(&mut self, part: Part) -> Result<Ack> {
+            let our_row = part
+                .rows
+                .get(&self.our_id)
+                .ok_or(Error::InvalidElderSignature)?;
+            let valid = part.commitment.row(Self::eval_point(self.our_id)) == our_row.commitment();
+            let dealer = part.dealer;
+            self.parts.insert(dealer, part);
+            Ok(Ack {
+                dealer,
+                acker: self.our_id,
+                valid,
+            })
+        }
+
+        /// Once more than `threshold` acks confirm every recorded `Part`, derives our
+        /// `SecretKeyShare` and the shared `PublicKeySet`.
+        pub fn finalize(&self, acks: &[Ack]) -> Result<(SecretKeyShare, PublicKeySet)> {
+            if self.parts.len() <= self.threshold {
+                return Err(Error::InvalidElderSignature);
+            }
+            for &dealer in self.parts.keys() {
+                let confirmations = acks
+                    .iter()
+                    .filter(|ack| ack.dealer == dealer && ack.valid)
+                    .count();
+                if confirmations <= self.threshold {
+                    return Err(Error::InvalidElderSignature);
+                }
+            }
+
+            let mut secret_share = None;
+            let mut commitment = None;
+            for part in self.parts.values() {
+                let row = &part.rows[&self.our_id];
+                let share = SecretKeyShare::from(row.evaluate(0u64));
+                secret_share = Some(match secret_share {
+                    Some(acc) => acc + share,
+                    None => share,
+                });
+                commitment = Some(match commitment {
+                    Some(acc) => acc + part.commitment.clone(),
+                    None => part.commitment.clone(),
+                });
+            }
+
+            let secret_share = secret_share.ok_or(Error::InvalidElderSignature)?;
+            let public_key_set: PublicKeySet = commitment
+                .ok_or(Error::InvalidElderSignature)?
+                .row(0)
+                .into();
+
+            Ok((secret_share, public_key_set))
+        }
+    }
+}
+
+/// Erasure-coded dissemination of large `SuperMajority` ballots.
+pub mod erasure {
+    use reed_solomon_erasure::galois_8::ReedSolomon;
+
+    use crate::vote::{Proposition, SignedVote};
+    use crate::{Error, Result};
+
+    use super::Consensus;
+
+    /// One erasure-coded shard of a serialized `SuperMajority` vote.
+    #[derive(Debug, Clone)]
+    pub struct Shard {
+        pub index: usize,
+        pub bytes: Vec<u8>,
+    }
+
+    impl<T: Proposition> Consensus<T> {
+        /// Splits `signed_vote` into `n_elders` Reed-Solomon shards (two when there are fewer
+        /// than two elders), any `data_shards` of which are enough to reconstruct it.
+        pub fn shard_super_majority_vote(&self, signed_vote: &SignedVote<T>) -> Result<Vec<Shard>> {
+            let (data_shards, parity_shards) = self.shard_counts();
+            let rs =
+                ReedSolomon::new(data_shards, parity_shards).map_err(|_| Error::InvalidElderSignature)?;
+
+            let bytes = bincode::serialize(signed_vote)?;
+            let shard_len = bytes.len().div_ceil(data_shards).max(1);
+            let mut shards: Vec<Vec<u8>> = bytes
+                .chunks(shard_len)
+                .map(|chunk| {
+                    let mut shard = chunk.to_vec();
+                    shard.resize(shard_len, 0);
+                    shard
+                })
+                .collect();
+            shards.resize(data_shards, vec![0; shard_len]);
+            shards.extend((0..parity_shards).map(|_| vec![0; shard_len]));
+
+            rs.encode(&mut shards).map_err(|_| Error::InvalidElderSignature)?;
+
+            Ok(shards
+                .into_iter()
+                .enumerate()
+                .map(|(index, bytes)| Shard { index, bytes })
+                .collect())
+        }
+
+        /// Reconstructs a `SignedVote` once `data_shards` shards are present, then validates
+        /// it as usual.
+        pub fn reassemble_super_majority(&self, shards: Vec<Shard>) -> Result<SignedVote<T>> {
+            let (data_shards, parity_shards) = self.shard_counts();
+            if shards.len() < data_shards {
+                return Err(Error::InvalidElderSignature);
+            }
+
+            let rs =
+                ReedSolomon::new(data_shards, parity_shards).map_err(|_| Error::InvalidElderSignature)?;
+
+            let mut shard_options: Vec<Option<Vec<u8>>> = vec![None; data_shards + parity_shards];
+            for shard in shards {
+                if let Some(slot) = shard_options.get_mut(shard.index) {
+                    *slot = Some(shard.bytes);
+                }
+            }
+
+            rs.reconstruct(&mut shard_options)
+                .map_err(|_| Error::InvalidElderSignature)?;
+
+            let mut bytes = Vec::new();
+            for shard in shard_options.into_iter().take(data_shards) {
+                bytes.extend(shard.ok_or(Error::InvalidElderSignature)?);
+            }
+
+            let signed_vote: SignedVote<T> = bincode::deserialize(&bytes)?;
+            self.validate_signed_vote(&signed_vote)?;
+            Ok(signed_vote)
+        }
+
+        fn shard_counts(&self) -> (usize, usize) {
+            let data_shards = (self.n_elders / 2).max(1);
+            let parity_shards = self.n_elders.saturating_sub(data_shards).max(1);
+            (data_shards, parity_shards)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blsttc::SecretKeySet;
+
+    fn test_consensus(n_elders: usize, threshold: usize, id: NodeId) -> Consensus<u8> {
+        let sk_set = SecretKeySet::random(threshold, &mut rand::thread_rng());
+        let secret_key = (id, sk_set.secret_key_share(id as u64));
+        Consensus::from(secret_key, sk_set.public_keys(), n_elders)
+    }
+
+    fn propose(gen: Generation, proposal: u8) -> Vote<u8> {
+        Vote {
+            gen,
+            ballot: Ballot::Propose(proposal),
+        }
+    }
+
+    #[test]
+    fn enforce_pending_caps_evicts_highest_generation_first() {
+        let mut c = test_consensus(4, 1, 0);
+        for gen in 0..(MAX_PENDING_VOTES_TOTAL as Generation + 1) {
+            let vote = c.sign_vote(propose(gen, 0)).unwrap();
+            c.pending.entry(gen).or_default().push(vote);
+        }
+
+        c.enforce_pending_caps();
+
+        assert!(c.pending.contains_key(&0), "lowest generation should survive eviction");
+        assert!(
+            !c.pending.contains_key(&(MAX_PENDING_VOTES_TOTAL as Generation)),
+            "highest generation should be evicted first"
+        );
+    }
+
+    #[test]
+    fn verify_fault_rejects_compatible_votes() {
+        let c = test_consensus(4, 1, 0);
+        let vote_a = c.sign_vote(propose(0, 1)).unwrap();
+        let vote_b = vote_a.clone();
+
+        let proof = Equivocation {
+            voter: 0,
+            gen: 0,
+            vote_a,
+            vote_b,
+        };
+
+        assert!(c.verify_fault(&proof).is_err());
+    }
+
+    #[test]
+    fn decision_shares_do_not_mix_across_proposals() {
+        let mut c = test_consensus(4, 1, 0);
+        let gen = 0;
+        let proposals_a = BTreeSet::from([1u8]);
+        let proposals_b = BTreeSet::from([2u8]);
+
+        let sig_a = c.sign_decision(gen, &proposals_a).unwrap();
+        let sig_b = c.sign_decision(gen, &proposals_b).unwrap();
+
+        c.handle_decision_share(gen, proposals_a.clone(), 0, sig_a).unwrap();
+        c.handle_decision_share(gen, proposals_b.clone(), 0, sig_b).unwrap();
+
+        assert_eq!(c.decision_shares[&(gen, proposals_a)].len(), 1);
+        assert_eq!(c.decision_shares[&(gen, proposals_b)].len(), 1);
+    }
+
+    #[test]
+    fn reassemble_super_majority_rejects_too_few_shards() {
+        let c = test_consensus(7, 3, 0);
+        let (data_shards, _) = c.shard_counts();
+        let signed_vote = c.sign_vote(propose(0, 1)).unwrap();
+        let shards = c.shard_super_majority_vote(&signed_vote).unwrap();
+
+        let too_few = shards.into_iter().take(data_shards - 1).collect();
+        assert!(c.reassemble_super_majority(too_few).is_err());
+    }
+
+    #[test]
+    fn dkg_participants_agree_on_the_same_public_key() {
+        use dkg::DkgSession;
+
+        let threshold = 1;
+        let participants = vec![0u8, 1, 2, 3];
+        let mut sessions: Vec<_> = participants
+            .iter()
+            .map(|&id| DkgSession::new(id, threshold, participants.clone()))
+            .collect();
+
+        let parts: Vec<_> = sessions.iter().map(DkgSession::our_part).collect();
+
+        let mut acks = Vec::new();
+        for session in &mut sessions {
+            for part in &parts {
+                acks.push(session.handle_part(part.clone()).unwrap());
+            }
+        }
+
+        let results: Vec<_> = sessions
+            .iter()
+            .map(|session| session.finalize(&acks).unwrap())
+            .collect();
+
+        let first_public_key_set = &results[0].1;
+        assert!(results
+            .iter()
+            .all(|(_, public_key_set)| public_key_set == first_public_key_set));
+
+        // The derived shares must also be usable as-is by `Consensus::sign`/`verify_sig_share`,
+        // which index by raw `NodeId` rather than the DKG's offset evaluation point.
+        for (&id, (secret_share, public_key_set)) in participants.iter().zip(&results) {
+            let consensus =
+                Consensus::<u8>::from((id, secret_share.clone()), public_key_set.clone(), participants.len());
+            let signed_vote = consensus.sign_vote(propose(0, 1)).unwrap();
+            consensus
+                .verify_sig_share(0, &signed_vote.vote, id, &signed_vote.sig)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn handle_timeout_rebroadcasts_after_interval_elapses() {
+        let mut c = test_consensus(4, 1, 0);
+        let gen = 0;
+        let our_vote = c.sign_vote(propose(gen, 1)).unwrap();
+        c.cast_vote(our_vote.clone());
+
+        match c.handle_timeout(gen, std::time::Duration::from_secs(0)).unwrap() {
+            VoteResponse::Broadcast(signed_vote) => {
+                assert_eq!(signed_vote.proposals(), our_vote.proposals());
+            }
+            _ => panic!("expected a re-broadcast of our latest vote"),
+        }
+    }
+
+    #[test]
+    fn handle_timeout_escalates_split_vote_to_merge() {
+        let mut c = test_consensus(5, 2, 0);
+        let gen = 0;
+
+        let our_vote = c.sign_vote(propose(gen, 0)).unwrap();
+        c.cast_vote(our_vote);
+        for (voter, proposal) in [(1u8, 1u8), (2, 2), (3, 3)] {
+            let vote = propose(gen, proposal);
+            let sig = c.sign(gen, &vote).unwrap();
+            c.log_signed_vote(&SignedVote { voter, sig, vote });
+        }
+
+        match c.handle_timeout(gen, std::time::Duration::from_secs(0)).unwrap() {
+            VoteResponse::Broadcast(signed_vote) => {
+                assert!(matches!(signed_vote.vote.ballot, Ballot::Merge(_)));
+            }
+            _ => panic!("expected a merge escalation"),
+        }
+    }
+
+    #[test]
+    fn handle_timeout_escalates_to_super_majority() {
+        let mut c = test_consensus(3, 1, 0);
+        let gen = 0;
+
+        let our_vote = c.sign_vote(propose(gen, 7)).unwrap();
+        c.cast_vote(our_vote);
+        let other_vote = propose(gen, 7);
+        let other_sig = c.sign(gen, &other_vote).unwrap();
+        c.log_signed_vote(&SignedVote {
+            voter: 1,
+            sig: other_sig,
+            vote: other_vote,
+        });
+
+        match c.handle_timeout(gen, std::time::Duration::from_secs(0)).unwrap() {
+            VoteResponse::Broadcast(signed_vote) => {
+                assert!(signed_vote.vote.is_super_majority_ballot());
+            }
+            _ => panic!("expected a super-majority escalation"),
         }
     }
 }